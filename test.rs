@@ -1,48 +1,17 @@
 // A Rust implementation of byte range locks
 
-/// Module implementing a map as a sorted vector as BTreeMap isn't up to needs
-mod vector_map {
-  #[derive(Clone)]
-  struct VectorMapItem<K, V> {
-    key: K,
-    value: V,
-  }
-  
-  /// A map implemented as a sorted vector
-  #[derive(Clone)]
-  pub struct VectorMap<K, V> {
-    root: Vec<VectorMapItem<K, V>>
-  }
-  
-  impl<K: Ord, V> VectorMap<K, V> {
-    pub fn new() -> VectorMap<K, V> {
-      VectorMap<K, V> { root : Vec::<VectorMapItem<K, V>>::new() }
-    }
-    
-    /// Find the nearest key matching
-    fn binary_search(&self, key : K) -> Result<usize, usize> {
-      let s = &self.root[..];
-      s.binary_search_by(|x| x.key.cmp(&key));
-    }
-    
-    /// Clears the map, removing all values
-    pub fn clear(&mut self) {
-      self.root.clear();
-    }
-    
-    /// Inserts a value into the map, returning any exact match formerly there if any
-    pub fn insert(&mut self, key : K, value : V) -> Option<V> {
-      match self.binary_search(key) {
-        Err(index) =>,
-        Ok(index) => let oldvalue = self.root[index].value; self.root[index].value = value; oldvalue,
-      }
-    }
-  }
-}
+mod vector_map;
 
 /// Module implementing file byte range locks
 mod file_byte_range_locks {
-  use std::collections::btree_map::BTreeMap;
+  use crate::vector_map::VectorMap;
+  use std::collections::hash_map::DefaultHasher;
+  use std::collections::{HashMap, VecDeque};
+  use std::future::Future;
+  use std::hash::{Hash, Hasher};
+  use std::pin::Pin;
+  use std::sync::{Arc, Mutex};
+  use std::task::{Context, Poll, Waker};
 
   /// A file descriptor
   pub type FileDescriptor = i32;
@@ -50,40 +19,545 @@ mod file_byte_range_locks {
   /// A start offset (inclusive) and end offset (exclusive)
   type ByteRange = (u64, u64);
 
+  /// The non-overlapping segments held for a file: each entry's key is the
+  /// segment's start and its value its end together with the `Lock` held over
+  /// it. A sorted vector beats a `BTreeMap` here since the segment sets
+  /// involved are small and scan-heavy.
+  type Segments = VectorMap<u64, (u64, Lock)>;
+
   /// A variant holding the type of lock for a range
+  #[derive(Clone, PartialEq)]
   enum Lock {
     /// This range is share locked by one or more fds
     Shared(Vec<FileDescriptor>),
     /// This range is exclusively locked by just this fd
     Exclusive(FileDescriptor),
   }
-  
-  /// The range locks associated with some file
-  pub struct Locks {
+
+  /// Does `lock`, held over some segment intersecting the request, conflict with
+  /// a new request from `fd` for `exclusive` access?
+  fn conflicts(lock: &Lock, fd: FileDescriptor, exclusive: bool) -> bool {
+    match *lock {
+      Lock::Exclusive(other) => other != fd,
+      Lock::Shared(ref fds) => exclusive && fds.iter().any(|&f| f != fd),
+    }
+  }
+
+  /// Returns every stored segment intersecting `[start, end)`, keyed by its
+  /// current bounds, along with a clone of the `Lock` it carries. `floor`
+  /// finds the one segment that might straddle `start` from the left (if
+  /// any), and `range` then narrows the scan to that point through `end`
+  /// with two more binary searches, rather than a linear scan from the very
+  /// first segment in the map.
+  fn intersecting(locked_regions: &Segments, start: u64, end: u64) -> Vec<(ByteRange, Lock)> {
+    let lower = match locked_regions.floor(&start) {
+      Some((&seg_start, _)) => seg_start,
+      None => start,
+    };
+    locked_regions.range(&lower, &end)
+      .filter(|&(_, &(seg_end, _))| seg_end > start)
+      .map(|(&seg_start, &(seg_end, ref lock))| ((seg_start, seg_end), lock.clone()))
+      .collect()
+  }
+
+  /// Releases whatever part of `[start, end)` `fd` holds, leaving any residual
+  /// shared sub-holds of other fds intact. Returns `false` if `fd` held no
+  /// part of the range. Shared by `Locks::unset_lock` and the cancellation
+  /// path in `SetLockFuture`'s `Drop`, both of which need to release a range
+  /// without going through `unset_lock`'s own locking.
+  fn release_range(locked_regions: &mut Segments, fd: FileDescriptor, range: ByteRange) -> bool {
+    let (start, end) = range;
+    let overlapping = intersecting(locked_regions, start, end);
+    let mut found = false;
+    for (key, lock) in overlapping {
+      let held_by_fd = match lock {
+        Lock::Exclusive(holder) => holder == fd,
+        Lock::Shared(ref fds) => fds.contains(&fd),
+      };
+      if !held_by_fd {
+        continue;
+      }
+      found = true;
+      locked_regions.remove(&key.0);
+      if key.0 < start {
+        locked_regions.insert(key.0, (start, lock.clone()));
+      }
+      if key.1 > end {
+        locked_regions.insert(end, (key.1, lock.clone()));
+      }
+      let covered_start = std::cmp::max(key.0, start);
+      let covered_end = std::cmp::min(key.1, end);
+      if let Lock::Shared(fds) = lock {
+        let remaining: Vec<FileDescriptor> = fds.into_iter().filter(|&f| f != fd).collect();
+        if !remaining.is_empty() {
+          locked_regions.insert(covered_start, (covered_end, Lock::Shared(remaining)));
+        }
+      }
+    }
+    found
+  }
+
+  /// Merges any two adjacent segments that carry an identical `Lock`, keeping
+  /// the map compact
+  fn coalesce(locked_regions: &mut Segments) {
+    let entries: Vec<(ByteRange, Lock)> = locked_regions.iter().map(|(&seg_start, &(seg_end, ref lock))| ((seg_start, seg_end), lock.clone())).collect();
+    locked_regions.clear();
+    let mut entries = entries.into_iter();
+    let mut run = match entries.next() {
+      Some(first) => first,
+      None => return,
+    };
+    for (key, lock) in entries {
+      if run.0.1 == key.0 && run.1 == lock {
+        run = ((run.0.0, key.1), lock);
+      } else {
+        locked_regions.insert(run.0.0, (run.0.1, run.1));
+        run = (key, lock);
+      }
+    }
+    locked_regions.insert(run.0.0, (run.0.1, run.1));
+  }
+
+  /// A request replaces whatever hold `fd` already had on a segment: if `lock`
+  /// is `fd`'s own exclusive hold, or a shared hold naming only `fd`, nothing of
+  /// it survives outside the new range. Other fds sharing the segment keep
+  /// their share regardless. Returns `None` if nothing is left to keep.
+  fn residual_lock(lock: &Lock, fd: FileDescriptor) -> Option<Lock> {
+    match *lock {
+      Lock::Exclusive(holder) => if holder == fd { None } else { Some(lock.clone()) },
+      Lock::Shared(ref fds) => {
+        let remaining: Vec<FileDescriptor> = fds.iter().cloned().filter(|&f| f != fd).collect();
+        if remaining.is_empty() { None } else { Some(Lock::Shared(remaining)) }
+      }
+    }
+  }
+
+  /// Tries to grant `fd`'s request for `range` against `locked_regions`, mutating
+  /// it and returning `true` on success; leaves it untouched and returns `false`
+  /// on conflict
+  fn try_set_lock(locked_regions: &mut Segments, fd: FileDescriptor, range: ByteRange, exclusive: bool) -> bool {
+    let (start, end) = range;
+    if start >= end {
+      return true;
+    }
+    let overlapping = intersecting(locked_regions, start, end);
+    if overlapping.iter().any(|(_, lock)| conflicts(lock, fd, exclusive)) {
+      return false;
+    }
+    // Remove the intersecting segments, keeping any shared holders we will
+    // now be sharing the range with, and re-inserting whatever part of each
+    // segment falls outside the requested range minus fd's own prior hold,
+    // since this request replaces fd's existing hold rather than extending it
+    let mut shared_with = Vec::new();
+    for (key, lock) in overlapping {
+      locked_regions.remove(&key.0);
+      if key.0 < start {
+        if let Some(residual) = residual_lock(&lock, fd) {
+          locked_regions.insert(key.0, (start, residual));
+        }
+      }
+      if key.1 > end {
+        if let Some(residual) = residual_lock(&lock, fd) {
+          locked_regions.insert(end, (key.1, residual));
+        }
+      }
+      if let Lock::Shared(fds) = lock {
+        for f in fds {
+          if !shared_with.contains(&f) {
+            shared_with.push(f);
+          }
+        }
+      }
+    }
+    shared_with.retain(|&f| f != fd);
+    let new_lock = if exclusive {
+      Lock::Exclusive(fd)
+    } else {
+      shared_with.push(fd);
+      Lock::Shared(shared_with)
+    };
+    locked_regions.insert(start, (end, new_lock));
+    coalesce(locked_regions);
+    true
+  }
+
+  /// A lock request parked on the wait queue because it conflicted at the time
+  /// it was made
+  struct Waiter {
+    fd: FileDescriptor,
+    range: ByteRange,
+    exclusive: bool,
+    granted: bool,
+    waker: Option<Waker>,
+  }
+
+  /// State shared between a `Locks` handle and any outstanding `SetLockFuture`s
+  /// it has handed out
+  struct LocksInner {
     /// The file name
     name: String,
     /// A sorted map of ranges to the file descriptors which hold them
-    locked_regions: BTreeMap<ByteRange, Lock>,
+    locked_regions: Segments,
+    /// Requests still waiting for a conflicting range to free up, in the order
+    /// they arrived
+    wait_queue: VecDeque<Arc<Mutex<Waiter>>>,
+    /// The shared-lock segments an fd held over a given range before
+    /// `upgrade_lock` escalated them to exclusive, so `downgrade_lock` can
+    /// restore them exactly. Keyed by (fd, range) rather than just fd, since
+    /// the same fd may have several disjoint ranges upgraded at once.
+    suspended: HashMap<(FileDescriptor, ByteRange), Vec<(ByteRange, Lock)>>,
+  }
+
+  /// Walks the wait queue in arrival order, granting every waiter that no longer
+  /// conflicts with the held regions or with an earlier, still-blocked waiter.
+  /// The latter check is what keeps this fair: a later shared request is never
+  /// allowed to jump the queue ahead of an earlier exclusive one it overlaps.
+  fn process_wait_queue(inner: &mut LocksInner) {
+    let mut shadow = inner.locked_regions.clone();
+    let mut still_waiting = VecDeque::new();
+    let mut blocked: Vec<(FileDescriptor, ByteRange, bool)> = Vec::new();
+    while let Some(entry) = inner.wait_queue.pop_front() {
+      let (fd, range, exclusive) = {
+        let waiter = entry.lock().unwrap();
+        (waiter.fd, waiter.range, waiter.exclusive)
+      };
+      let behind_blocked = blocked.iter().any(|&(other, (s, e), other_exclusive)| {
+        other != fd && (exclusive || other_exclusive) && s < range.1 && range.0 < e
+      });
+      if !behind_blocked && try_set_lock(&mut shadow, fd, range, exclusive) {
+        try_set_lock(&mut inner.locked_regions, fd, range, exclusive);
+        let mut waiter = entry.lock().unwrap();
+        waiter.granted = true;
+        if let Some(waker) = waiter.waker.take() {
+          waker.wake();
+        }
+      } else {
+        blocked.push((fd, range, exclusive));
+        still_waiting.push_back(entry);
+      }
+    }
+    inner.wait_queue = still_waiting;
+  }
+
+  /// The range locks associated with some file
+  #[derive(Clone)]
+  pub struct Locks {
+    inner: Arc<Mutex<LocksInner>>,
   }
 
   /// Possible unlock errors
   #[derive(Debug)]
   pub enum UnsetLockError { NotFound }
 
+  /// Possible upgrade errors
+  #[derive(Debug)]
+  pub enum UpgradeLockError {
+    /// Some part of the requested range is not locked at all, so this fd holds
+    /// no shared lock there to escalate
+    NotHeld,
+    /// Some part of the requested range is held other than as a shared lock by
+    /// this fd alone: another fd shares it or holds it exclusively, or this fd
+    /// already holds it exclusively
+    Conflict,
+  }
+
+  /// Possible downgrade errors
+  #[derive(Debug)]
+  pub enum DowngradeLockError {
+    /// There is no suspended shared-lock state recorded for this fd
+    NotSuspended,
+  }
+
   impl Locks {
     pub fn new(name: String) -> Locks {
-      Locks { name : name, locked_regions : BTreeMap::<ByteRange, Lock>::new() }
+      Locks {
+        inner: Arc::new(Mutex::new(LocksInner {
+          name: name,
+          locked_regions: Segments::new(),
+          wait_queue: VecDeque::new(),
+          suspended: HashMap::new(),
+        })),
+      }
     }
-  
+
     /// Sets a lock for a given byte range, returning false if not possible
     pub fn set_lock(&mut self, fd: FileDescriptor, range: ByteRange, exclusive: bool) -> Result<bool, ()> {
-      Ok(true)
+      let mut inner = self.inner.lock().unwrap();
+      Ok(try_set_lock(&mut inner.locked_regions, fd, range, exclusive))
+    }
+
+    /// Sets a lock for a given byte range, asynchronously waiting its turn on the
+    /// wait queue rather than failing fast if the range currently conflicts
+    pub fn set_lock_async(&mut self, fd: FileDescriptor, range: ByteRange, exclusive: bool) -> SetLockFuture {
+      let mut inner = self.inner.lock().unwrap();
+      if try_set_lock(&mut inner.locked_regions, fd, range, exclusive) {
+        return SetLockFuture { inner: self.inner.clone(), waiter: None };
+      }
+      let waiter = Arc::new(Mutex::new(Waiter {
+        fd: fd,
+        range: range,
+        exclusive: exclusive,
+        granted: false,
+        waker: None,
+      }));
+      inner.wait_queue.push_back(waiter.clone());
+      SetLockFuture { inner: self.inner.clone(), waiter: Some(waiter) }
     }
 
     /// Unsets a lock for a given byte range
     pub fn unset_lock(&mut self, fd: FileDescriptor, range: ByteRange) -> Result<(), UnsetLockError> {
+      let mut inner = self.inner.lock().unwrap();
+      let (start, end) = range;
+      if start >= end {
+        return Ok(());
+      }
+      if !release_range(&mut inner.locked_regions, fd, range) {
+        return Err(UnsetLockError::NotFound);
+      }
+      coalesce(&mut inner.locked_regions);
+      process_wait_queue(&mut inner);
+      Ok(())
+    }
+
+    /// Escalates `fd`'s shared hold on `range` to exclusive, provided `fd` is
+    /// the only sharer across the whole range, recording the prior shared state
+    /// so `downgrade_lock` can restore it exactly
+    pub fn upgrade_lock(&mut self, fd: FileDescriptor, range: ByteRange) -> Result<(), UpgradeLockError> {
+      let mut inner = self.inner.lock().unwrap();
+      let (start, end) = range;
+      if start >= end {
+        return Ok(());
+      }
+      let overlapping = intersecting(&inner.locked_regions, start, end);
+      if overlapping.is_empty() {
+        return Err(UpgradeLockError::NotHeld);
+      }
+      // The overlapping segments must tile [start, end) with no gaps: a gap
+      // means fd doesn't actually hold that part of the range, so there is
+      // nothing there to escalate.
+      let mut cursor = start;
+      for (key, lock) in &overlapping {
+        if key.0 > cursor {
+          return Err(UpgradeLockError::NotHeld);
+        }
+        match *lock {
+          Lock::Shared(ref fds) if fds.len() == 1 && fds[0] == fd => {}
+          _ => return Err(UpgradeLockError::Conflict),
+        }
+        cursor = key.1;
+      }
+      if cursor < end {
+        return Err(UpgradeLockError::NotHeld);
+      }
+      let prior: Vec<(ByteRange, Lock)> = overlapping.iter().map(|&(key, ref lock)| ((std::cmp::max(key.0, start), std::cmp::min(key.1, end)), lock.clone())).collect();
+      inner.suspended.insert((fd, range), prior);
+      for (key, lock) in overlapping {
+        inner.locked_regions.remove(&key.0);
+        if key.0 < start {
+          inner.locked_regions.insert(key.0, (start, lock.clone()));
+        }
+        if key.1 > end {
+          inner.locked_regions.insert(end, (key.1, lock));
+        }
+        inner.locked_regions.insert(std::cmp::max(key.0, start), (std::cmp::min(key.1, end), Lock::Exclusive(fd)));
+      }
+      coalesce(&mut inner.locked_regions);
+      Ok(())
+    }
+
+    /// Restores `fd`'s shared hold on `range` that a prior `upgrade_lock`
+    /// suspended
+    pub fn downgrade_lock(&mut self, fd: FileDescriptor, range: ByteRange) -> Result<(), DowngradeLockError> {
+      let mut inner = self.inner.lock().unwrap();
+      let prior = match inner.suspended.remove(&(fd, range)) {
+        Some(prior) => prior,
+        None => return Err(DowngradeLockError::NotSuspended),
+      };
+      let (start, end) = range;
+      // Only drop the segments this fd's own upgrade put in place (the ones
+      // it is exclusive over), not whatever else now intersects the range —
+      // another fd may have legitimately locked part of it since the upgrade.
+      for (key, lock) in intersecting(&inner.locked_regions, start, end) {
+        if lock == Lock::Exclusive(fd) {
+          inner.locked_regions.remove(&key.0);
+          if key.0 < start {
+            inner.locked_regions.insert(key.0, (start, lock.clone()));
+          }
+          if key.1 > end {
+            inner.locked_regions.insert(end, (key.1, lock));
+          }
+        }
+      }
+      for (key, lock) in prior {
+        inner.locked_regions.insert(key.0, (key.1, lock));
+      }
+      coalesce(&mut inner.locked_regions);
+      process_wait_queue(&mut inner);
       Ok(())
     }
+
+    /// Reports whether a hypothetical `set_lock(fd, range, exclusive)` would
+    /// succeed, without mutating anything. If it would not, returns the first
+    /// conflicting holder's fd together with the specific sub-range that
+    /// conflicts, mirroring `fcntl`'s `F_GETLK`.
+    pub fn test_lock(&self, fd: FileDescriptor, range: ByteRange, exclusive: bool) -> Option<(FileDescriptor, ByteRange)> {
+      let inner = self.inner.lock().unwrap();
+      let (start, end) = range;
+      if start >= end {
+        return None;
+      }
+      for (key, lock) in intersecting(&inner.locked_regions, start, end) {
+        let holder = match lock {
+          Lock::Exclusive(other) if other != fd => Some(other),
+          Lock::Shared(ref fds) if exclusive => fds.iter().cloned().find(|&f| f != fd),
+          _ => None,
+        };
+        if let Some(holder) = holder {
+          let overlap = (std::cmp::max(key.0, start), std::cmp::min(key.1, end));
+          return Some((holder, overlap));
+        }
+      }
+      None
+    }
+
+    /// Releases every range held by `fd` anywhere in this file, drops any
+    /// suspended upgrade state it held, and cancels any of its requests still
+    /// parked on the wait queue. Ranges `fd` does not hold are ignored. Used
+    /// when a file descriptor closes.
+    pub fn release_all(&mut self, fd: FileDescriptor) {
+      let mut inner = self.inner.lock().unwrap();
+      inner.wait_queue.retain(|waiter| waiter.lock().unwrap().fd != fd);
+      let all: Vec<(ByteRange, Lock)> = inner.locked_regions.iter().map(|(&seg_start, &(seg_end, ref lock))| ((seg_start, seg_end), lock.clone())).collect();
+      let mut changed = false;
+      for (key, lock) in all {
+        match lock {
+          Lock::Exclusive(holder) if holder == fd => {
+            inner.locked_regions.remove(&key.0);
+            changed = true;
+          }
+          Lock::Shared(fds) if fds.contains(&fd) => {
+            inner.locked_regions.remove(&key.0);
+            let remaining: Vec<FileDescriptor> = fds.into_iter().filter(|&f| f != fd).collect();
+            if !remaining.is_empty() {
+              inner.locked_regions.insert(key.0, (key.1, Lock::Shared(remaining)));
+            }
+            changed = true;
+          }
+          _ => {}
+        }
+      }
+      inner.suspended.retain(|&(holder, _), _| holder != fd);
+      if changed {
+        coalesce(&mut inner.locked_regions);
+        process_wait_queue(&mut inner);
+      }
+    }
+  }
+
+  /// Future returned by `Locks::set_lock_async`, resolved either immediately or
+  /// once `unset_lock` grants the parked waiter its turn
+  pub struct SetLockFuture {
+    inner: Arc<Mutex<LocksInner>>,
+    waiter: Option<Arc<Mutex<Waiter>>>,
+  }
+
+  impl Future for SetLockFuture {
+    type Output = Result<(), ()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+      let granted = self.waiter.as_ref().map_or(false, |waiter| waiter.lock().unwrap().granted);
+      if granted {
+        // The caller now holds the range, so dropping this future must not release it
+        self.waiter = None;
+      }
+      match self.waiter {
+        None => Poll::Ready(Ok(())),
+        Some(ref waiter) => {
+          let mut waiter = waiter.lock().unwrap();
+          if waiter.granted {
+            Poll::Ready(Ok(()))
+          } else {
+            waiter.waker = Some(cx.waker().clone());
+            Poll::Pending
+          }
+        }
+      }
+    }
+  }
+
+  impl Drop for SetLockFuture {
+    /// A future dropped before resolving (e.g. it lost a `select!` or was
+    /// wrapped in a timeout) must not leave its `Waiter` parked forever. If it
+    /// had already been granted by a `process_wait_queue` run racing with the
+    /// drop, nobody will ever call `unset_lock` for it, so release the range
+    /// here instead of leaking it.
+    fn drop(&mut self) {
+      let waiter = match self.waiter.take() {
+        Some(waiter) => waiter,
+        None => return,
+      };
+      let mut inner = self.inner.lock().unwrap();
+      inner.wait_queue.retain(|entry| !Arc::ptr_eq(entry, &waiter));
+      let (fd, range, granted) = {
+        let waiter = waiter.lock().unwrap();
+        (waiter.fd, waiter.range, waiter.granted)
+      };
+      if granted {
+        release_range(&mut inner.locked_regions, fd, range);
+        coalesce(&mut inner.locked_regions);
+        process_wait_queue(&mut inner);
+      }
+    }
+  }
+
+  /// Number of shards a `LockManager` distributes its files across. Each shard
+  /// is guarded by its own mutex, so operations on files hashing to different
+  /// shards can proceed without contending on a single global lock.
+  const SHARD_COUNT: usize = 16;
+
+  /// Tracks the `Locks` for many files at once, bucketed across a fixed number
+  /// of shards so that concurrent callers working on different files don't
+  /// serialize behind one another
+  pub struct LockManager {
+    shards: Vec<Mutex<HashMap<String, Locks>>>,
+  }
+
+  impl LockManager {
+    pub fn new() -> LockManager {
+      let mut shards = Vec::with_capacity(SHARD_COUNT);
+      for _ in 0..SHARD_COUNT {
+        shards.push(Mutex::new(HashMap::new()));
+      }
+      LockManager { shards: shards }
+    }
+
+    /// Hashes `name` to the shard responsible for it
+    fn shard_for(&self, name: &str) -> &Mutex<HashMap<String, Locks>> {
+      let mut hasher = DefaultHasher::new();
+      name.hash(&mut hasher);
+      let index = (hasher.finish() as usize) % self.shards.len();
+      &self.shards[index]
+    }
+
+    /// Runs `f` against the `Locks` for `name`, creating it lazily, while
+    /// holding only the shard that `name` hashes to
+    pub fn with_file<F, R>(&self, name: &str, f: F) -> R where F: FnOnce(&mut Locks) -> R {
+      let shard = self.shard_for(name);
+      let mut files = shard.lock().unwrap();
+      let locks = files.entry(name.to_string()).or_insert_with(|| Locks::new(name.to_string()));
+      f(locks)
+    }
+
+    /// Releases every range held by `fd` across every file tracked by this
+    /// manager, sweeping all shards. Used when a file descriptor closes.
+    pub fn release_all(&self, fd: FileDescriptor) {
+      for shard in &self.shards {
+        let mut files = shard.lock().unwrap();
+        for locks in files.values_mut() {
+          locks.release_all(fd);
+        }
+      }
+    }
   }
 }
 
@@ -109,6 +583,166 @@ mod test {
       ($value:expr) => ($value)
   }
 
+  /// Drives a future to completion on the current thread, for tests that have
+  /// no need of a full executor
+  fn block_on<F: std::future::Future>(mut f: F) -> F::Output {
+    use std::task::{Context, RawWaker, RawWakerVTable, Waker};
+    fn noop(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker { RawWaker::new(std::ptr::null(), &VTABLE) }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+    let mut f = unsafe { std::pin::Pin::new_unchecked(&mut f) };
+    loop {
+      if let std::task::Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+        return v;
+      }
+    }
+  }
+
+  #[test]
+  fn async_lock_waits_its_turn() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 2), true).ok(), Some(true));
+    // fd 2 conflicts, so this future parks on the wait queue rather than failing
+    let waiting = f.set_lock_async(2, (0, 2), true);
+    // Releasing fd 1's range should grant fd 2's parked request
+    assert_eq!(f.unset_lock(1, (0, 2)).ok(), Some(()));
+    assert_eq!(block_on(waiting), Ok(()));
+  }
+
+  #[test]
+  fn dropping_a_pending_future_cancels_its_waiter() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 10), true).ok(), Some(true));
+    // fd 2 conflicts and parks, then gives up before ever being granted
+    let waiting = f.set_lock_async(2, (0, 10), true);
+    drop(waiting);
+    assert_eq!(f.unset_lock(1, (0, 10)).ok(), Some(()));
+    // fd 2's cancelled request must not have been granted in its place
+    assert_eq!(f.set_lock(3, (0, 10), true).ok(), Some(true));
+  }
+
+  #[test]
+  fn dropping_a_future_granted_without_being_polled_releases_the_range() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 10), true).ok(), Some(true));
+    let waiting = f.set_lock_async(2, (0, 10), true);
+    // fd 1's release grants fd 2's waiter, but fd 2 never polls the future
+    // (e.g. it lost a select! race) before dropping it
+    assert_eq!(f.unset_lock(1, (0, 10)).ok(), Some(()));
+    drop(waiting);
+    // The range fd 2 was granted but never observed must not be leaked
+    assert_eq!(f.set_lock(3, (0, 10), true).ok(), Some(true));
+  }
+
+  #[test]
+  fn release_all_cancels_its_own_parked_waiters() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 10), true).ok(), Some(true));
+    // fd 2 conflicts and parks on the wait queue
+    let _waiting = f.set_lock_async(2, (0, 10), true);
+    // fd 2 closes before ever getting its turn
+    f.release_all(2);
+    // Releasing fd 1's range must not hand fd 2's cancelled request the lock
+    assert_eq!(f.unset_lock(1, (0, 10)).ok(), Some(()));
+    assert_eq!(f.set_lock(3, (0, 10), true).ok(), Some(true));
+  }
+
+  #[test]
+  fn upgrade_and_downgrade_lock() {
+    use super::file_byte_range_locks::{Locks, UpgradeLockError};
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 2), false).ok(), Some(true));
+    // fd 2 also sharing means fd 1 is no longer the sole sharer, so upgrade must fail
+    assert_eq!(f.set_lock(2, (0, 2), false).ok(), Some(true));
+    assert!(matches!(f.upgrade_lock(1, (0, 2)).err(), Some(UpgradeLockError::Conflict)));
+    assert_eq!(f.unset_lock(2, (0, 2)).ok(), Some(()));
+    // Now fd 1 is the only sharer, so the upgrade succeeds
+    assert_eq!(f.upgrade_lock(1, (0, 2)).ok(), Some(()));
+    // The range is now held exclusively, so a second exclusive request must fail
+    assert_eq!(f.set_lock(2, (0, 2), true).ok(), Some(false));
+    // Downgrading restores fd 1's shared hold, freeing the range up for fd 2 to share it
+    assert_eq!(f.downgrade_lock(1, (0, 2)).ok(), Some(()));
+    assert_eq!(f.set_lock(2, (0, 2), false).ok(), Some(true));
+  }
+
+  #[test]
+  fn upgrade_lock_tracks_concurrent_upgrades_on_disjoint_ranges() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    // fd 1 shares two disjoint ranges and upgrades both at once
+    assert_eq!(f.set_lock(1, (0, 5), false).ok(), Some(true));
+    assert_eq!(f.set_lock(1, (10, 15), false).ok(), Some(true));
+    assert_eq!(f.upgrade_lock(1, (0, 5)).ok(), Some(()));
+    assert_eq!(f.upgrade_lock(1, (10, 15)).ok(), Some(()));
+    // Downgrading (0, 5) must restore fd 1's shared hold there, not erase it
+    assert_eq!(f.downgrade_lock(1, (0, 5)).ok(), Some(()));
+    assert_eq!(f.set_lock(2, (0, 5), false).ok(), Some(true));
+    // The still-outstanding upgrade over (10, 15) must be untouched
+    assert_eq!(f.set_lock(2, (10, 15), true).ok(), Some(false));
+    assert_eq!(f.downgrade_lock(1, (10, 15)).ok(), Some(()));
+    assert_eq!(f.set_lock(2, (10, 15), false).ok(), Some(true));
+  }
+
+  #[test]
+  fn upgrade_lock_rejects_a_gap_in_the_held_range() {
+    use super::file_byte_range_locks::{Locks, UpgradeLockError};
+    let mut f = Locks::new("foo".to_string());
+    // fd 1 only holds (0, 5); (5, 10) is completely unlocked, so escalating
+    // the wider (0, 10) range must fail rather than silently granting it
+    assert_eq!(f.set_lock(1, (0, 5), false).ok(), Some(true));
+    assert!(matches!(f.upgrade_lock(1, (0, 10)).err(), Some(UpgradeLockError::NotHeld)));
+    // fd 2 can still freely lock the hole fd 1 never held
+    assert_eq!(f.set_lock(2, (5, 10), true).ok(), Some(true));
+  }
+
+  #[test]
+  fn downgrade_lock_does_not_clobber_a_lock_taken_during_the_upgrade() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 5), false).ok(), Some(true));
+    assert_eq!(f.upgrade_lock(1, (0, 5)).ok(), Some(()));
+    // fd 2 legitimately locks the adjacent range while fd 1 is upgraded
+    assert_eq!(f.set_lock(2, (5, 10), true).ok(), Some(true));
+    // Downgrading fd 1's (0, 5) must not touch fd 2's (5, 10)
+    assert_eq!(f.downgrade_lock(1, (0, 5)).ok(), Some(()));
+    assert_eq!(f.set_lock(3, (5, 10), true).ok(), Some(false));
+  }
+
+  #[test]
+  fn test_lock_reports_conflicting_holder() {
+    use super::file_byte_range_locks::Locks;
+    let mut f = Locks::new("foo".to_string());
+    assert_eq!(f.set_lock(1, (0, 4), true).ok(), Some(true));
+    // fd 2 probing an overlapping exclusive request is told who and where it conflicts
+    assert_eq!(f.test_lock(2, (2, 6), true), Some((1, (2, 4))));
+    // fd 1 probing its own range sees no conflict
+    assert_eq!(f.test_lock(1, (0, 4), true), None);
+    // A range outside the lock is free
+    assert_eq!(f.test_lock(2, (4, 6), true), None);
+    // Querying alone must not have mutated anything
+    assert_eq!(f.set_lock(2, (4, 6), true).ok(), Some(true));
+  }
+
+  #[test]
+  fn lock_manager_shards_by_file_and_releases_all() {
+    use super::file_byte_range_locks::LockManager;
+    let manager = LockManager::new();
+    // Two different files are created lazily and tracked independently
+    assert_eq!(manager.with_file("foo", |f| f.set_lock(1, (0, 2), true).ok()), Some(true));
+    assert_eq!(manager.with_file("bar", |f| f.set_lock(1, (0, 2), true).ok()), Some(true));
+    assert_eq!(manager.with_file("foo", |f| f.set_lock(2, (0, 2), true).ok()), Some(false));
+    // Closing fd 1 releases its ranges in every file
+    manager.release_all(1);
+    assert_eq!(manager.with_file("foo", |f| f.set_lock(2, (0, 2), true).ok()), Some(true));
+    assert_eq!(manager.with_file("bar", |f| f.set_lock(2, (0, 2), true).ok()), Some(true));
+  }
+
   #[test]
   fn two_write_locks_exclude() {
     use super::file_byte_range_locks::{Locks, UnsetLockError};
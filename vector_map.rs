@@ -1,95 +1,172 @@
 /// Module implementing a map as a sorted vector as BTreeMap isn't up to needs
-mod vector_map {
-  use std::mem;
-  
-  #[derive(Clone)]
-  struct VectorMapItem<K, V> {
-    pub key: K,
-    pub value: V,
-  }
-  
-  /// A map implemented as a sorted vector
-  #[derive(Clone)]
-  pub struct VectorMap<K, V> {
-    root: Vec<VectorMapItem<K, V>>
-  }
-  
-  impl<K: Ord, V> VectorMap<K, V> {
-    pub fn new() -> VectorMap<K, V> {
-      VectorMap { root : Vec::<VectorMapItem<K, V>>::new() }
-    }
-    
-    /// Find the nearest key matching
-    fn binary_search(&self, key : &K) -> Result<usize, usize> {
-      let s = &self.root[..];
-      s.binary_search_by(|x| x.key.cmp(key))
-    }
-    
-    /// Clears the map, removing all values
-    pub fn clear(&mut self) {
-      self.root.clear();
-    }
-    
-    /// Returns the length of the map
-    pub fn len(&self) -> usize { self.root.len() }
-    
-    /// Return if the map is empty
-    pub fn is_empty(&self) -> bool { self.root.is_empty() }
-    
-    /// Returns a reference to an exact match (Ok) or nearest match (Err) for the specified key
-    pub fn get(&self, key: &K) -> Result<&VectorMapItem<K, V>, &VectorMapItem<K, V>> {
-      match self.binary_search(key) {
-        Err(index) => Err(&self.root[index]),
-        Ok(index) => Ok(&self.root[index]),
-      }
-    }
-    
-    /// Inserts a value into the map, returning any exact match formerly there if any
-    pub fn insert(&mut self, key : K, value : V) -> Option<V> {
-      match self.binary_search(&key) {
-        // Insert value
-        Err(index) => {
-          self.root.insert(index, VectorMapItem { key : key, value : value});
-          None
-        },
-        // Replace value
-        Ok(index) => {
-          let mut v = value;
-          {
-            let x = &mut v;
-            let y = &mut self.root[index].value;
-            mem::swap(x, y);
-          }
-          Some(v)
-        },
-      }
-    }
-    
-    /// Returns an iterator
-    pub fn iter(&self) -> Iter<K, V> {
-      self.root.iter()
+use std::mem;
+
+#[derive(Clone)]
+pub struct VectorMapItem<K, V> {
+  pub key: K,
+  pub value: V,
+}
+
+/// A map implemented as a sorted vector
+#[derive(Clone)]
+pub struct VectorMap<K, V> {
+  root: Vec<VectorMapItem<K, V>>
+}
+
+impl<K: Ord, V> VectorMap<K, V> {
+  pub fn new() -> VectorMap<K, V> {
+    VectorMap { root : Vec::<VectorMapItem<K, V>>::new() }
+  }
+
+  /// Find the nearest key matching
+  fn binary_search(&self, key : &K) -> Result<usize, usize> {
+    let s = &self.root[..];
+    s.binary_search_by(|x| x.key.cmp(key))
+  }
+
+  /// Clears the map, removing all values
+  pub fn clear(&mut self) {
+    self.root.clear();
+  }
+
+  /// Returns the length of the map
+  pub fn len(&self) -> usize { self.root.len() }
+
+  /// Return if the map is empty
+  pub fn is_empty(&self) -> bool { self.root.is_empty() }
+
+  /// Returns a reference to an exact match (Ok) or nearest match (Err) for the specified key
+  pub fn get(&self, key: &K) -> Result<&VectorMapItem<K, V>, &VectorMapItem<K, V>> {
+    match self.binary_search(key) {
+      Err(index) => Err(&self.root[index]),
+      Ok(index) => Ok(&self.root[index]),
     }
-    
-    /// Returns an iterator over the keys
-    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
-      Keys(self.iter().map(|i|{ i.key }))
+  }
+
+  /// Inserts a value into the map, returning any exact match formerly there if any
+  pub fn insert(&mut self, key : K, value : V) -> Option<V> {
+    match self.binary_search(&key) {
+      // Insert value
+      Err(index) => {
+        self.root.insert(index, VectorMapItem { key : key, value : value});
+        None
+      },
+      // Replace value
+      Ok(index) => {
+        let mut v = value;
+        {
+          let x = &mut v;
+          let y = &mut self.root[index].value;
+          mem::swap(x, y);
+        }
+        Some(v)
+      },
     }
-    
-    /// Returns an iterator over the values
-    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
-      Values(self.iter().map(|i|{ i.value }))
+  }
+
+  /// Removes an exact match for the specified key, returning its value if any
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    match self.binary_search(key) {
+      Ok(index) => Some(self.root.remove(index).value),
+      Err(_) => None,
     }
   }
-  
-  pub struct Iter<'a, K:'a, V:'a> {
-    iter: Vec<VectorMapItem<K, V>>
+
+  /// Returns the entry with the greatest key `<= key`, if any, found with a
+  /// single binary search
+  pub fn floor(&self, key: &K) -> Option<(&K, &V)> {
+    let index = match self.binary_search(key) {
+      Ok(index) => index,
+      Err(0) => return None,
+      Err(index) => index - 1,
+    };
+    let item = &self.root[index];
+    Some((&item.key, &item.value))
+  }
+
+  /// Returns an iterator over the entries whose keys fall in `[start, end)`,
+  /// found with two binary searches for the range's endpoints rather than a
+  /// linear scan
+  pub fn range(&self, start: &K, end: &K) -> Iter<'_, K, V> {
+    let lo = match self.binary_search(start) {
+      Ok(index) => index,
+      Err(index) => index,
+    };
+    let hi = match self.binary_search(end) {
+      Ok(index) => index,
+      Err(index) => index,
+    };
+    Iter { iter: self.root[lo..hi].iter() }
+  }
+
+  /// Returns an iterator
+  pub fn iter(&self) -> Iter<'_, K, V> {
+    Iter { iter: self.root.iter() }
+  }
+
+  /// Returns an iterator over the keys
+  pub fn keys(&self) -> Keys<'_, K, V> {
+    Keys(self.iter())
+  }
+
+  /// Returns an iterator over the values
+  pub fn values(&self) -> Values<'_, K, V> {
+    Values(self.iter())
+  }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+  iter: std::slice::Iter<'a, VectorMapItem<K, V>>,
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+  type Item = (&'a K, &'a V);
+  fn next(&mut self) -> Option<Self::Item> {
+    self.iter.next().map(|item| (&item.key, &item.value))
+  }
+}
+
+pub struct Keys<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K: 'a, V: 'a> Iterator for Keys<'a, K, V> {
+  type Item = &'a K;
+  fn next(&mut self) -> Option<Self::Item> {
+    self.0.next().map(|(key, _)| key)
+  }
+}
+
+pub struct Values<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K: 'a, V: 'a> Iterator for Values<'a, K, V> {
+  type Item = &'a V;
+  fn next(&mut self) -> Option<Self::Item> {
+    self.0.next().map(|(_, value)| value)
+  }
+}
+
+pub struct IntoIter<K, V> {
+  iter: std::vec::IntoIter<VectorMapItem<K, V>>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+  type Item = (K, V);
+  fn next(&mut self) -> Option<Self::Item> {
+    self.iter.next().map(|item| (item.key, item.value))
+  }
+}
+
+impl<K: Ord, V> IntoIterator for VectorMap<K, V> {
+  type Item = (K, V);
+  type IntoIter = IntoIter<K, V>;
+  fn into_iter(self) -> IntoIter<K, V> {
+    IntoIter { iter: self.root.into_iter() }
+  }
 }
 
 #[cfg(test)]
 mod test {
   // Quite breathtakingly, Rust 1.0 doesn't allow comparison of enum values :(
   // Taken from https://github.com/SimonSapin/rust-std-candidates/blob/master/matches/lib.rs
-  #[macro_export]
   macro_rules! matches {
       ($expression: expr, $($pattern:tt)+) => {
           _tt_as_expr_hack! {
@@ -102,14 +179,13 @@ mod test {
   }
 
   /// Work around "error: unexpected token: `an interpolated tt`", whatever that means.
-  #[macro_export]
   macro_rules! _tt_as_expr_hack {
       ($value:expr) => ($value)
   }
 
   #[test]
   fn insert_works() {
-    use super::vector_map::VectorMap;
+    use super::VectorMap;
     let mut v = VectorMap::new();
     assert_eq!(v.insert(10, "niall10"), None);
     assert_eq!(v.insert(40, "niall40"), None);
@@ -117,12 +193,44 @@ mod test {
     assert_eq!(v.insert(30, "niall30"), None);
     assert_eq!(v.insert(0, "niall0"), None);
     assert_eq!(v.len(), 5);
-    for i in v {
-      println!("{}", i);
+    for (k, v) in v.iter() {
+      println!("{} -> {}", k, v);
     }
     // Exact match
-    assert!(matches!(v.get(&10).ok(), Some(x) if x.value == "niall0"));
+    assert!(matches!(v.get(&10).ok(), Some(x) if x.value == "niall10"));
     v.clear();
     assert!(v.is_empty());
   }
+
+  #[test]
+  fn remove_and_range_work() {
+    use super::VectorMap;
+    let mut v = VectorMap::new();
+    for key in [0u64, 10, 20, 30, 40] {
+      v.insert(key, key * 2);
+    }
+    // range(10, 30) picks out the two entries whose keys fall in [10, 30)
+    assert_eq!(v.range(&10, &30).map(|(&k, &val)| (k, val)).collect::<Vec<_>>(), vec![(10, 20), (20, 40)]);
+    assert_eq!(v.keys().cloned().collect::<Vec<_>>(), vec![0, 10, 20, 30, 40]);
+    assert_eq!(v.values().cloned().collect::<Vec<_>>(), vec![0, 20, 40, 60, 80]);
+    assert_eq!(v.remove(&20), Some(40));
+    assert_eq!(v.remove(&20), None);
+    assert_eq!(v.len(), 4);
+    assert_eq!(v.into_iter().collect::<Vec<_>>(), vec![(0, 0), (10, 20), (30, 60), (40, 80)]);
+  }
+
+  #[test]
+  fn floor_finds_the_predecessor_or_none() {
+    use super::VectorMap;
+    let mut v = VectorMap::new();
+    for key in [10u64, 20, 30] {
+      v.insert(key, key * 2);
+    }
+    // An exact match is its own floor
+    assert_eq!(v.floor(&20), Some((&20, &40)));
+    // A key between two entries floors to the lower one
+    assert_eq!(v.floor(&25), Some((&20, &40)));
+    // A key before every entry has no floor
+    assert_eq!(v.floor(&5), None);
+  }
 }